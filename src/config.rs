@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+/// Settings loaded from the `--config` TOML file. Currently only carries per-device nicknames,
+/// but is the natural place to grow other deployment-wide settings.
+#[derive(Deserialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    nicknames: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = fs::read_to_string(path)?;
+
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// The human-friendly name configured for `user_name`/`device_name`, if any.
+    pub fn nickname(&self, user_name: &str, device_name: &str) -> Option<&str> {
+        self.nicknames.get(&format!("{user_name}/{device_name}")).map(String::as_str)
+    }
+
+    /// Resolves `identifier`, taken as a nickname configured for `user_name`, to the raw device
+    /// name it aliases. Returns `None` when no nickname matches; a raw device name is not
+    /// recognised here unless it is also configured as a nickname.
+    pub fn resolve_device_name(&self, user_name: &str, identifier: &str) -> Option<String> {
+        let prefix = format!("{user_name}/");
+
+        self.nicknames.iter()
+            .find(|(key, nickname)| key.starts_with(&prefix) && nickname.as_str() == identifier)
+            .map(|(key, _)| key[prefix.len()..].to_string())
+    }
+}
@@ -0,0 +1,141 @@
+// Serializers for `/api/0/locations?format=...` alternatives to the default JSON body.
+//
+// These intentionally stay separate from `location.rs`, which owns the OwnTracks wire format;
+// this module only ever reads a `Location`, it never (de)serializes one.
+
+use std::fmt::Write as _;
+
+use chrono::SecondsFormat;
+use serde::Serialize;
+
+use crate::location::Location;
+
+#[derive(Serialize)]
+pub struct FeatureCollection {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+
+    pub features: Vec<Feature>,
+}
+
+#[derive(Serialize)]
+pub struct Feature {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+
+    pub geometry: Geometry,
+    pub properties: serde_json::Value,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    Point { coordinates: Vec<f64> },
+    LineString { coordinates: Vec<Vec<f64>> },
+}
+
+impl Feature {
+    fn point(location: &Location) -> Feature {
+        let mut coordinates = vec![location.longitude as f64, location.latitude as f64];
+        if let Some(altitude) = location.altitude {
+            coordinates.push(altitude as f64);
+        }
+
+        Feature {
+            kind: "Feature",
+            geometry: Geometry::Point { coordinates },
+            properties: serde_json::json!({
+                "tst": location.timestamp.timestamp(),
+                "acc": location.accuracy,
+                "vel": location.velocity,
+                "batt": location.battery,
+                "tid": location.tracker_id,
+            }),
+        }
+    }
+
+    fn line_string(locations: &[Location]) -> Feature {
+        Feature {
+            kind: "Feature",
+            geometry: Geometry::LineString {
+                coordinates: locations.iter()
+                    .map(|location| {
+                        let mut coordinates = vec![location.longitude as f64, location.latitude as f64];
+                        if let Some(altitude) = location.altitude {
+                            coordinates.push(altitude as f64);
+                        }
+                        coordinates
+                    })
+                    .collect(),
+            },
+            properties: serde_json::json!({}),
+        }
+    }
+}
+
+/// Builds a `FeatureCollection` with one `Point` feature per location, in the order the
+/// locations were given, plus a trailing `LineString` feature tracing the whole track when
+/// there are at least two locations.
+pub fn locations_to_geojson(locations: &[Location]) -> FeatureCollection {
+    let mut features: Vec<Feature> = locations.iter().map(Feature::point).collect();
+
+    if locations.len() > 1 {
+        features.push(Feature::line_string(locations));
+    }
+
+    FeatureCollection {
+        kind: "FeatureCollection",
+        features,
+    }
+}
+
+/// Builds a GPX 1.1 document with a single `<trk>`/`<trkseg>` containing one `<trkpt>` per
+/// location, in the order the locations were given.
+pub fn locations_to_gpx(locations: &[Location]) -> String {
+    let mut gpx = String::new();
+
+    gpx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str("<gpx version=\"1.1\" creator=\"oxidetracks\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n");
+    gpx.push_str("  <trk>\n");
+    gpx.push_str("    <trkseg>\n");
+
+    for location in locations {
+        let _ = writeln!(
+            gpx,
+            "      <trkpt lat=\"{}\" lon=\"{}\">",
+            location.latitude, location.longitude,
+        );
+
+        if let Some(altitude) = location.altitude {
+            let _ = writeln!(gpx, "        <ele>{}</ele>", altitude);
+        }
+
+        let _ = writeln!(
+            gpx,
+            "        <time>{}</time>",
+            location.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+        );
+
+        if location.velocity.is_some() || location.course.is_some() {
+            gpx.push_str("        <extensions>\n");
+
+            if let Some(velocity) = location.velocity {
+                let _ = writeln!(gpx, "          <speed>{}</speed>", velocity);
+            }
+
+            if let Some(course) = location.course {
+                let _ = writeln!(gpx, "          <course>{}</course>", course);
+            }
+
+            gpx.push_str("        </extensions>\n");
+        }
+
+        gpx.push_str("      </trkpt>\n");
+    }
+
+    gpx.push_str("    </trkseg>\n");
+    gpx.push_str("  </trk>\n");
+    gpx.push_str("</gpx>\n");
+
+    gpx
+}
@@ -1,25 +1,33 @@
 mod storage;
 mod location;
+mod export;
+mod config;
 
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use log::{info, error};
 use macros::IntoJsonResponse;
 
+use config::Config;
 use location::Location;
-use storage::Storage;
+use storage::{Storage, DeviceStorage, Share};
 use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
 
-use std::{sync::{Arc, Mutex}, path::PathBuf, time::Instant};
+use std::{collections::HashMap, sync::{Arc, Mutex}, path::PathBuf, time::Instant};
 
 use git_version::git_version;
 use axum::{
-    routing::get,
+    routing::{get, post, delete},
     Router,
     Server,
-    extract::{State, Query}, Json
+    extract::{State, Query, Path, ws::{WebSocket, WebSocketUpgrade, Message}},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json
 };
+use tokio::sync::broadcast;
+use rand::Rng;
 
 use serde::{Serialize, Deserialize};
 use clap::Parser;
@@ -45,6 +53,9 @@ struct ListQuery {
 #[derive(Serialize, IntoJsonResponse)]
 struct ListResponse {
     results: Vec<String>,
+
+    // Maps a raw name from `results` to its configured nickname, if any.
+    nicknames: HashMap<String, String>,
 }
 
 #[derive(Serialize, IntoJsonResponse)]
@@ -65,16 +76,34 @@ async fn get_list(State(app_state): State<AppState>, Query(query): Query<ListQue
     match &query.user {
         Some(user) => {
             match storage.user(user) {
-                Some(user_storage) => Ok(ListResponse { results: user_storage.device_names() }),
+                Some(user_storage) => {
+                    let results = user_storage.device_names();
+                    let nicknames = results.iter()
+                        .filter_map(|device_name| app_state.config.nickname(user, device_name)
+                            .map(|nickname| (device_name.clone(), nickname.to_string())))
+                        .collect();
+
+                    Ok(ListResponse { results, nicknames })
+                }
                 // Mirroring what owntracks/recorder would do
                 None => Err(ErrorResponse { error: "Cannot open requested directory".to_string() })
             }
         }
-        None => Ok(ListResponse { results: storage.user_names() })
+        None => Ok(ListResponse { results: storage.user_names(), nicknames: HashMap::new() })
     }
 }
 
-type LastResponse = Json<Vec<Location>>;
+#[derive(Serialize)]
+struct LastEntry {
+    user: String,
+    device: String,
+    nickname: Option<String>,
+
+    #[serde(flatten)]
+    location: Location,
+}
+
+type LastResponse = Json<Vec<LastEntry>>;
 
 async fn get_last(State(app_state): State<AppState>) -> Result<LastResponse, ErrorResponse> {
     let storage = &app_state.storage.lock()
@@ -82,10 +111,13 @@ async fn get_last(State(app_state): State<AppState>) -> Result<LastResponse, Err
 
     Ok(Json(storage.users().iter()
         .flat_map(|(user_name, user_storage)| user_storage.devices().iter()
-            .filter(|(_, device_storage)| device_storage.last_location().is_some())
-            .map(|(device_name, device_storage)| device_storage.last_location().clone())
-            .flatten()
-            .map(|location| location.clone()))
+            .filter_map(|(device_name, device_storage)| device_storage.last_location()
+                .map(|location| LastEntry {
+                    nickname: app_state.config.nickname(user_name, device_name).map(str::to_string),
+                    user: user_name.clone(),
+                    device: device_name.clone(),
+                    location,
+                })))
         .collect()))
 }
 
@@ -105,7 +137,7 @@ struct LocationsQuery {
     format: String
 }
 
-#[derive(Serialize, IntoJsonResponse)]
+#[derive(Serialize)]
 struct LocationsResponse {
     count: usize,
     data: Vec<Location>,
@@ -113,34 +145,321 @@ struct LocationsResponse {
     status: u16,
 }
 
-async fn get_locations(State(app_state): State<AppState>, Query(query): Query<LocationsQuery>) -> Result<LocationsResponse, ErrorResponse> {
+impl IntoResponse for LocationsResponse {
+    fn into_response(self) -> Response {
+        Json(self).into_response()
+    }
+}
+
+/// The body of `/api/0/locations`, shaped by the `format` query parameter. Unlike the other
+/// handlers this can't use `#[derive(IntoJsonResponse)]`, since `format=geojson`/`format=gpx`
+/// need a `Content-Type` other than `application/json`.
+enum LocationsExport {
+    Json(LocationsResponse),
+    GeoJson(export::FeatureCollection),
+    Gpx(String),
+}
+
+impl IntoResponse for LocationsExport {
+    fn into_response(self) -> Response {
+        match self {
+            LocationsExport::Json(response) => response.into_response(),
+            LocationsExport::GeoJson(feature_collection) => match serde_json::to_string(&feature_collection) {
+                Ok(body) => ([(header::CONTENT_TYPE, "application/geo+json")], body).into_response(),
+                Err(error) => ErrorResponse::new(&format!("Unable to serialize GeoJSON response: {error}")).into_response(),
+            },
+            LocationsExport::Gpx(body) => ([(header::CONTENT_TYPE, "application/gpx+xml")], body).into_response(),
+        }
+    }
+}
+
+/// Looks up a device by its raw name, falling back to treating `identifier` as a nickname
+/// configured for `user_name` in the TOML config.
+fn resolve_device<'a>(storage: &'a Storage, config: &Config, user_name: &str, identifier: &str) -> Option<&'a DeviceStorage> {
+    let user_storage = storage.user(user_name)?;
+
+    user_storage.device(identifier)
+        .or_else(|| config.resolve_device_name(user_name, identifier)
+            .and_then(|device_name| user_storage.device(&device_name)))
+}
+
+async fn get_locations(State(app_state): State<AppState>, Query(query): Query<LocationsQuery>) -> Result<LocationsExport, ErrorResponse> {
     let storage = &app_state.storage.lock()
         .map_err(|_| ErrorResponse::new("Unable to take lock for in memory storage"))?;
 
     let started_fetching = Instant::now();
 
-    let locations: Vec<Location> = storage.user(query.user_name.as_str())
-        .and_then(|user_storage| user_storage.device(query.device_name.as_str()))
-        .map(|device_storage| device_storage.locations()
-            .iter()
-            .skip_while(|location| location.timestamp <= query.from)
-            .take_while(|location| location.timestamp <= query.to)
-            .map(Location::clone)
-            .collect())
+    let locations: Vec<Location> = resolve_device(storage, &app_state.config, &query.user_name, &query.device_name)
+        .map(|device_storage| device_storage.locations_in_range(query.from, query.to))
         .unwrap_or(vec![]);
 
     info!(target: "API", "Fetched {} locations in {:.2?}", locations.len(), started_fetching.elapsed());
 
-    Ok(LocationsResponse {
-        count: locations.len(),
-        status: 200,
-        data: locations,
+    Ok(match query.format.as_str() {
+        "geojson" => LocationsExport::GeoJson(export::locations_to_geojson(&locations)),
+        "gpx" => LocationsExport::Gpx(export::locations_to_gpx(&locations)),
+        _ => LocationsExport::Json(LocationsResponse {
+            count: locations.len(),
+            status: 200,
+            data: locations,
+        }),
     })
 }
 
+/// Parses an OwnTracks HTTP-mode `topic` such as `owntracks/jane/phone` into `(user, device)`,
+/// mirroring how the recorder falls back to it when the `X-Limit-U`/`X-Limit-D` headers are absent.
+fn user_and_device_from_topic(topic: &str) -> Option<(String, String)> {
+    let mut segments = topic.trim_start_matches('/').split('/');
+    segments.next()?;
+
+    let user_name = segments.next()?;
+    let device_name = segments.next()?;
+
+    Some((user_name.to_string(), device_name.to_string()))
+}
+
+fn user_and_device(headers: &HeaderMap, location: &Location) -> Option<(String, String)> {
+    let user_name = headers.get("X-Limit-U").and_then(|value| value.to_str().ok());
+    let device_name = headers.get("X-Limit-D").and_then(|value| value.to_str().ok());
+
+    if let (Some(user_name), Some(device_name)) = (user_name, device_name) {
+        return Some((user_name.to_string(), device_name.to_string()));
+    }
+
+    location.topic.as_deref().and_then(user_and_device_from_topic)
+}
+
+async fn post_pub(State(app_state): State<AppState>, headers: HeaderMap, Json(location): Json<Location>) -> Result<StatusCode, ErrorResponse> {
+    let (user_name, device_name) = user_and_device(&headers, &location)
+        .ok_or_else(|| ErrorResponse::new("Unable to determine user/device from X-Limit-U/X-Limit-D headers or topic"))?;
+
+    let mut storage = app_state.storage.lock()
+        .map_err(|_| ErrorResponse::new("Unable to take lock for in memory storage"))?;
+
+    storage.ingest(&user_name, &device_name, location.clone())
+        .map_err(|error| ErrorResponse::new(&format!("Unable to persist location: {error}")))?;
+
+    // No receivers is the common case between pushes to a given device and the next `/ws/last`
+    // subscriber, so ignore the "no one is listening" error this returns.
+    let _ = app_state.location_tx.send(IngestedLocation { user_name, device_name, location });
+
+    Ok(StatusCode::OK)
+}
+
+/// A location plus the user/device it was filed under, broadcast to `/ws/last` subscribers so
+/// they can filter on identity without having to infer it back out of the `Location` itself.
+#[derive(Clone)]
+struct IngestedLocation {
+    user_name: String,
+    device_name: String,
+    location: Location,
+}
+
+#[derive(Deserialize)]
+struct WsLastQuery {
+    user: Option<String>,
+    device: Option<String>,
+}
+
+impl WsLastQuery {
+    fn matches(&self, user_name: &str, device_name: &str) -> bool {
+        self.user.as_deref().map(|user| user == user_name).unwrap_or(true)
+            && self.device.as_deref().map(|device| device == device_name).unwrap_or(true)
+    }
+}
+
+async fn ws_last(ws: WebSocketUpgrade, State(app_state): State<AppState>, Query(query): Query<WsLastQuery>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_ws_last(socket, app_state, query))
+}
+
+async fn handle_ws_last(mut socket: WebSocket, app_state: AppState, query: WsLastQuery) {
+    let mut receiver = app_state.location_tx.subscribe();
+
+    let backlog: Vec<Location> = {
+        let Ok(storage) = app_state.storage.lock() else {
+            error!(target: "API", "Unable to take lock for in memory storage while sending /ws/last backlog");
+            return;
+        };
+
+        storage.users().iter()
+            .flat_map(|(user_name, user_storage)| user_storage.devices().iter()
+                .map(move |(device_name, device_storage)| (user_name, device_name, device_storage)))
+            .filter(|(user_name, device_name, _)| query.matches(user_name, device_name))
+            .filter_map(|(_, _, device_storage)| device_storage.last_location())
+            .collect()
+    };
+
+    for location in backlog {
+        let Ok(json) = serde_json::to_string(&location) else { continue };
+
+        if socket.send(Message::Text(json)).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => {
+                // We don't expect clients to send anything, so messages from the client are
+                // ignored; the loop only ends once the connection is closed and `recv` yields
+                // `None`.
+                if incoming.is_none() {
+                    return;
+                }
+            }
+            broadcast = receiver.recv() => {
+                let ingested = match broadcast {
+                    Ok(ingested) => ingested,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+
+                if !query.matches(&ingested.user_name, &ingested.device_name) {
+                    continue;
+                }
+
+                let Ok(json) = serde_json::to_string(&ingested.location) else { continue };
+
+                if socket.send(Message::Text(json)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Compares two byte strings of equal length in constant time, to avoid leaking how many leading
+/// bytes of a guess matched a secret (e.g. `admin_token`) through response timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn is_admin(headers: &HeaderMap, app_state: &AppState) -> bool {
+    let Some(admin_token) = &app_state.admin_token else { return false };
+
+    headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()))
+}
+
+fn generate_share_token() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[derive(Deserialize)]
+struct ShareRequest {
+    user: String,
+    device: String,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, IntoJsonResponse)]
+struct ShareResponse {
+    token: String,
+}
+
+async fn post_share(State(app_state): State<AppState>, headers: HeaderMap, Json(request): Json<ShareRequest>) -> Result<ShareResponse, (StatusCode, ErrorResponse)> {
+    if !is_admin(&headers, &app_state) {
+        return Err((StatusCode::UNAUTHORIZED, ErrorResponse::new("Missing or invalid admin bearer token")));
+    }
+
+    let storage = app_state.storage.lock()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, ErrorResponse::new("Unable to take lock for in memory storage")))?;
+
+    let token = generate_share_token();
+
+    storage.create_share(&token, &Share {
+        user_name: request.user,
+        device_name: request.device,
+        expires_at: request.expires_at,
+    }).map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, ErrorResponse::new(&format!("Unable to persist share: {error}"))))?;
+
+    Ok(ShareResponse { token })
+}
+
+async fn delete_share(State(app_state): State<AppState>, headers: HeaderMap, Path(token): Path<String>) -> Result<StatusCode, (StatusCode, ErrorResponse)> {
+    if !is_admin(&headers, &app_state) {
+        return Err((StatusCode::UNAUTHORIZED, ErrorResponse::new("Missing or invalid admin bearer token")));
+    }
+
+    let storage = app_state.storage.lock()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, ErrorResponse::new("Unable to take lock for in memory storage")))?;
+
+    let revoked = storage.revoke_share(&token)
+        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, ErrorResponse::new(&format!("Unable to revoke share: {error}"))))?;
+
+    if revoked {
+        Ok(StatusCode::OK)
+    } else {
+        Err((StatusCode::NOT_FOUND, ErrorResponse::new("No such share token")))
+    }
+}
+
+/// Upper bound on `SharedQuery::history_hours`: far more than anyone would plausibly want in a
+/// share link, but comfortably inside the range `chrono::Duration`/`DateTime` can represent
+/// without panicking.
+const MAX_HISTORY_HOURS: i64 = 24 * 365 * 10;
+
+#[derive(Deserialize)]
+struct SharedQuery {
+    /// When set, also return up to this many hours of recent history alongside `last`.
+    /// Must be in `0..=MAX_HISTORY_HOURS`.
+    history_hours: Option<i64>,
+}
+
+#[derive(Serialize, IntoJsonResponse)]
+struct SharedLocationResponse {
+    last: Location,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    history: Vec<Location>,
+}
+
+async fn get_shared(State(app_state): State<AppState>, Path(token): Path<String>, Query(query): Query<SharedQuery>) -> Result<SharedLocationResponse, StatusCode> {
+    let storage = app_state.storage.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let share = storage.share(&token).ok().flatten().ok_or(StatusCode::NOT_FOUND)?;
+
+    if share.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let device_storage = storage.user(&share.user_name)
+        .and_then(|user_storage| user_storage.device(&share.device_name))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let last = device_storage.last_location().ok_or(StatusCode::NOT_FOUND)?;
+
+    let history = match query.history_hours {
+        Some(hours) => {
+            if !(0..=MAX_HISTORY_HOURS).contains(&hours) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+
+            let to = Utc::now();
+            let from = to - chrono::Duration::hours(hours);
+
+            device_storage.locations_in_range(from, to)
+        }
+        None => Vec::new(),
+    };
+
+    Ok(SharedLocationResponse { last, history })
+}
+
 #[derive(Clone)]
 struct AppState {
-    storage: Arc<Mutex<Storage>>
+    storage: Arc<Mutex<Storage>>,
+    location_tx: broadcast::Sender<IngestedLocation>,
+    config: Arc<Config>,
+    admin_token: Option<String>,
 }
 
 #[derive(clap::Parser)]
@@ -153,20 +472,39 @@ struct Arguments {
     /// The address to bind to
     #[clap(short, long, env, default_value = "[::]:3000")]
     bind: String,
+
+    /// The path to a TOML configuration file (e.g. for per-device nicknames)
+    #[arg(short, long, env)]
+    config: Option<PathBuf>,
+
+    /// Bearer token required to call admin-guarded endpoints (e.g. POST /api/0/share). Leaving
+    /// this unset disables those endpoints.
+    #[arg(short, long, env)]
+    admin_token: Option<String>,
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::init_from_env(env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"));
 
-    let Arguments { storage_path, bind } = Arguments::parse();
+    let Arguments { storage_path, bind, config, admin_token } = Arguments::parse();
 
-    let mut storage = Storage::new(storage_path);
+    let config = match config {
+        Some(config_path) => Config::load(&config_path).unwrap(),
+        None => Config::default(),
+    };
+
+    let mut storage = Storage::new(storage_path).unwrap();
 
     storage.read_from_fs().unwrap();
 
+    let (location_tx, _) = broadcast::channel(1024);
+
     let state = AppState {
-        storage: Arc::new(Mutex::new(storage))
+        storage: Arc::new(Mutex::new(storage)),
+        location_tx,
+        config: Arc::new(config),
+        admin_token,
     };
 
     let app = Router::new()
@@ -174,6 +512,11 @@ async fn main() {
         .route("/api/0/list", get(get_list))
         .route("/api/0/last", get(get_last))
         .route("/api/0/locations", get(get_locations))
+        .route("/pub", post(post_pub))
+        .route("/ws/last", get(ws_last))
+        .route("/api/0/share", post(post_share))
+        .route("/api/0/share/:token", delete(delete_share))
+        .route("/api/0/shared/:token", get(get_shared))
         .with_state(state)
         .layer(ServiceBuilder::new()
             .layer(CorsLayer::permissive()));
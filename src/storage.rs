@@ -3,16 +3,71 @@ use std::ffi::OsStr;
 use std::path::PathBuf;
 use std::time::Instant;
 use std::fs::{self, OpenOptions};
-use std::io::{prelude::*, BufReader};
+use std::io::{prelude::*, BufReader, SeekFrom};
 
 use anyhow::{ensure, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
 use log::{debug, info};
+use serde::{Serialize, Deserialize};
 
 use crate::location::Location;
 
+/// A minted `/api/0/share` token's grant: read-only access to one user/device's locations,
+/// optionally expiring.
+#[derive(Serialize, Deserialize)]
+pub struct Share {
+    pub user_name: String,
+    pub device_name: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Builds the composite `sled` key for a single location:
+/// `user\0device\0<big-endian i64 tst><big-endian u64 seq>`. Fixed-width, so lexicographic byte
+/// order matches `(user, device, timestamp, seq)` order, which is exactly what range scans over
+/// a device's history need.
+///
+/// `seq` is a process-wide monotonically increasing id (see [`sled::Tree::generate_id`]), not
+/// just the timestamp, so that two locations for the same device landing in the same second
+/// (`tst` only has second resolution) get distinct keys instead of silently clobbering each
+/// other the way a plain `insert` on `(user, device, timestamp)` alone would.
+fn location_key(user_name: &str, device_name: &str, timestamp: i64, seq: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(user_name.len() + device_name.len() + 2 + 8 + 8);
+    key.extend_from_slice(user_name.as_bytes());
+    key.push(0);
+    key.extend_from_slice(device_name.as_bytes());
+    key.push(0);
+    key.extend_from_slice(&timestamp.to_be_bytes());
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+/// Whether `value` is safe to use as a single path component under `base_path` (e.g. the
+/// `user_name`/`device_name` an unauthenticated `/pub` publish supplies via `X-Limit-U`/
+/// `X-Limit-D` or the `topic` field). Rejects anything that could escape the intended directory
+/// or otherwise isn't a plain name: empty, `.`/`..`, or containing a path separator or NUL byte.
+fn is_valid_path_component(value: &str) -> bool {
+    !value.is_empty()
+        && value != "."
+        && value != ".."
+        && !value.contains(['/', '\\', '\0'])
+}
+
+fn device_prefix(user_name: &str, device_name: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(user_name.len() + device_name.len() + 2);
+    prefix.extend_from_slice(user_name.as_bytes());
+    prefix.push(0);
+    prefix.extend_from_slice(device_name.as_bytes());
+    prefix.push(0);
+    prefix
+}
+
 pub struct Storage {
     base_path: PathBuf,
 
+    locations: sled::Tree,
+    file_meta: sled::Tree,
+    shares: sled::Tree,
+
     users: HashMap<String, UserStorage>,
 }
 
@@ -20,8 +75,11 @@ pub struct UserStorage {
     devices: HashMap<String, DeviceStorage>,
 }
 
+#[derive(Clone)]
 pub struct DeviceStorage {
-    locations: Vec<Location>,
+    locations: sled::Tree,
+    user_name: String,
+    device_name: String,
 }
 
 impl UserStorage {
@@ -32,17 +90,59 @@ impl UserStorage {
 }
 
 impl DeviceStorage {
-    pub fn locations(&self) -> &Vec<Location> { &self.locations }
-    pub fn last_location(&self) -> Option<&Location> { self.locations.last() }
+    /// The device's whole history, oldest first. Prefer [`DeviceStorage::locations_in_range`]
+    /// where possible; this scans every key under the device's prefix.
+    pub fn locations(&self) -> Vec<Location> {
+        self.locations.scan_prefix(device_prefix(&self.user_name, &self.device_name))
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(_, value)| serde_json::from_slice(&value).ok())
+            .collect()
+    }
+
+    /// Matches the old `Vec`-based store's `(from, to]` semantics: a location exactly at `from`
+    /// is excluded, one exactly at `to` is included. The `sled` range itself has to be inclusive
+    /// on both ends (spanning the full `seq` suffix at each boundary timestamp), so the exact
+    /// edges are trimmed back off by timestamp afterwards.
+    pub fn locations_in_range(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Location> {
+        let start = location_key(&self.user_name, &self.device_name, from.timestamp(), 0);
+        let end = location_key(&self.user_name, &self.device_name, to.timestamp(), u64::MAX);
+
+        self.locations.range(start..=end)
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(_, value)| serde_json::from_slice::<Location>(&value).ok())
+            .filter(|location| location.timestamp > from && location.timestamp <= to)
+            .collect()
+    }
+
+    pub fn last_location(&self) -> Option<Location> {
+        self.locations.scan_prefix(device_prefix(&self.user_name, &self.device_name))
+            .next_back()
+            .and_then(|entry| entry.ok())
+            .and_then(|(_, value)| serde_json::from_slice(&value).ok())
+    }
+
+    fn insert(&self, location: &Location) -> Result<()> {
+        let seq = self.locations.generate_id()?;
+        let key = location_key(&self.user_name, &self.device_name, location.timestamp.timestamp(), seq);
+        self.locations.insert(key, serde_json::to_vec(location)?)?;
+
+        Ok(())
+    }
 }
 
 impl Storage {
-    pub fn new(base_path: PathBuf) -> Storage {
-        Storage {
+    pub fn new(base_path: PathBuf) -> Result<Storage> {
+        let db = sled::open(base_path.join("db"))?;
+
+        Ok(Storage {
             base_path,
 
+            locations: db.open_tree("locations")?,
+            file_meta: db.open_tree("file_meta")?,
+            shares: db.open_tree("shares")?,
+
             users: HashMap::new(),
-        }
+        })
     }
 
     pub fn users(&self) -> &HashMap<String, UserStorage> { &self.users }
@@ -50,6 +150,29 @@ impl Storage {
 
     pub fn user(&self, user_name: &str) -> Option<&UserStorage> { self.users.get(user_name) }
 
+    pub fn create_share(&self, token: &str, share: &Share) -> Result<()> {
+        self.shares.insert(token, serde_json::to_vec(share)?)?;
+
+        Ok(())
+    }
+
+    pub fn share(&self, token: &str) -> Result<Option<Share>> {
+        self.shares.get(token)?
+            .map(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            .transpose()
+    }
+
+    /// Revokes a previously minted share token, if it still exists. Returns whether a share was
+    /// actually removed, so the caller can distinguish an already-gone token from a fresh revoke.
+    pub fn revoke_share(&self, token: &str) -> Result<bool> {
+        Ok(self.shares.remove(token)?.is_some())
+    }
+
+    /// Discovers users/devices from the `last/` tree (as before), then ingests each device's
+    /// `rec/` history into `sled` incrementally: `file_meta` tracks how many bytes of each
+    /// history file have already been consumed, so a file that's grown since the last run (e.g.
+    /// a historical import appending to it out of band) only has its new lines re-parsed, rather
+    /// than the whole file being skipped or, worse, re-ingested from the start and duplicated.
     pub fn read_from_fs(&mut self) -> Result<()> {
         let base_path = &self.base_path;
         let base_path_str = base_path.to_str().unwrap_or("None");
@@ -81,62 +204,145 @@ impl Storage {
                 let last_directory_for_user_and_device = last_directory_for_user_and_device?.path();
                 let device_name = last_directory_for_user_and_device.file_name().map(OsStr::to_str).flatten().unwrap_or("None");
 
-                let last_file_for_user_and_device = last_directory_for_user_and_device
-                    .join(PathBuf::from("{user_name}-{device_name}.json"));
-
                 let device_storage = DeviceStorage {
-                    locations: Vec::new(),
+                    locations: self.locations.clone(),
+                    user_name: user_name.to_string(),
+                    device_name: device_name.to_string(),
                 };
 
                 self.users.get_mut(user_name).unwrap().devices.insert(device_name.to_string(), device_storage);
 
                 // NOTE: We don't actually read the file at storage-directory/last/{user}/{device}/{user}-{device}.json,
-                //       the in memory representation of the location history is entirely loaded from the history
-                //       directory. We do however write out an updated latest file with each sync.
+                //       the location history lives entirely in `sled`. We do however write out an
+                //       updated latest file with each sync.
             }
         }
 
-        for (user_name, user_storage) in &mut self.users {
-            for (device_name, device_storage) in &mut user_storage.devices {
+        let mut ingested_locations = 0usize;
+        let mut skipped_files = 0usize;
+
+        for (user_name, user_storage) in &self.users {
+            for device_name in user_storage.devices.keys() {
                 let history_directory_for_user_and_device = history_directory.join(user_name).join(device_name);
 
                 for history_for_user_and_device_in_month in history_directory_for_user_and_device.read_dir()? {
-                    let history_file = OpenOptions::new()
+                    let history_file_path = history_for_user_and_device_in_month?.path();
+
+                    let file_meta_key = history_file_path.to_string_lossy().into_owned();
+                    let file_len = history_file_path.metadata()?.len();
+
+                    let previously_ingested_offset = self.file_meta.get(&file_meta_key)?
+                        .and_then(|bytes| bytes.as_ref().try_into().ok())
+                        .map(u64::from_be_bytes)
+                        .unwrap_or(0);
+
+                    if previously_ingested_offset >= file_len {
+                        skipped_files += 1;
+                        continue;
+                    }
+
+                    let mut history_file = OpenOptions::new()
                         .append(true)
                         .read(true)
-                        .open(history_for_user_and_device_in_month?.path())?;
+                        .open(&history_file_path)?;
+
+                    history_file.seek(SeekFrom::Start(previously_ingested_offset))?;
+
+                    let mut consumed_offset = previously_ingested_offset;
 
                     for line in BufReader::new(&history_file).lines() {
                         let line = line?;
 
+                        // +1 for the newline `writeln!` appends and `BufRead::lines` strips.
+                        consumed_offset += line.len() as u64 + 1;
+
                         // Same as location.timestamp apparently.
                         let _: String = line.chars().take_while(|char| char != &'\t').collect();
                         let json: String = line.chars().skip_while(|char| char != &'{').collect();
 
                         let location: Location = serde_json::from_str(&json)?;
 
-                        device_storage.locations.push(location);
+                        let seq = self.locations.generate_id()?;
+                        let key = location_key(user_name, device_name, location.timestamp.timestamp(), seq);
+                        self.locations.insert(key, serde_json::to_vec(&location)?)?;
+
+                        ingested_locations += 1;
                     }
+
+                    self.file_meta.insert(file_meta_key, consumed_offset.to_be_bytes().to_vec())?;
                 }
             }
         }
 
-        info!(target: "Storage", "Loading took {:.2?}, loaded {} user(s) with a total of {} location(s)", started_loading.elapsed(), self.users.len(), self.users.iter()
-            .flat_map(|(_, user_storage)| &user_storage.devices)
-            .map(|(_, device_storage)| &device_storage.locations)
-            .map(|locations| locations.len())
-            .fold(0, |acc, len| acc + len));
+        info!(target: "Storage", "Loading took {:.2?}, ingested {} location(s) across {} user(s), skipped {} unchanged history file(s)", started_loading.elapsed(), ingested_locations, self.users.len(), skipped_files);
+
+        Ok(())
+    }
 
-        let started_sorting = Instant::now();
+    /// Persists a freshly published `Location` for `user_name`/`device_name` to both the
+    /// history (`rec/`) and last-known-position (`last/`) trees, then inserts it into `sled`.
+    ///
+    /// `user_name`/`device_name` come straight from an unauthenticated request, so they're
+    /// validated as plain path components before anything is joined onto `base_path` below.
+    pub fn ingest(&mut self, user_name: &str, device_name: &str, location: Location) -> Result<()> {
+        ensure!(is_valid_path_component(user_name), "Invalid user name '{user_name}'");
+        ensure!(is_valid_path_component(device_name), "Invalid device name '{device_name}'");
+
+        self.append_to_history(user_name, device_name, &location)?;
+        self.write_last_snapshot(user_name, device_name, &location)?;
+
+        let locations_tree = self.locations.clone();
+
+        let device_storage = self.users.entry(user_name.to_string())
+            .or_insert_with(|| UserStorage { devices: HashMap::new() })
+            .devices.entry(device_name.to_string())
+            .or_insert_with(|| DeviceStorage {
+                locations: locations_tree,
+                user_name: user_name.to_string(),
+                device_name: device_name.to_string(),
+            });
+
+        device_storage.insert(&location)
+    }
 
-        for device_storage in self.users.iter_mut()
-            .flat_map(|(_, user_storage)| &mut user_storage.devices)
-            .map(|(_, device_storage)| device_storage)
-        {
-            device_storage.locations.sort_by(|x, y| x.timestamp.cmp(&y.timestamp));
-        }
+    fn append_to_history(&self, user_name: &str, device_name: &str, location: &Location) -> Result<()> {
+        let history_directory_for_user_and_device = self.base_path.join("rec").join(user_name).join(device_name);
+        fs::create_dir_all(&history_directory_for_user_and_device)?;
+
+        let history_file_name = format!("{}.rec", location.timestamp.format("%Y-%m"));
+        let history_file_path = history_directory_for_user_and_device.join(history_file_name);
+
+        let mut history_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&history_file_path)?;
+
+        writeln!(
+            history_file,
+            "{}\t{}",
+            location.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+            serde_json::to_string(location)?,
+        )?;
+
+        // Keep `file_meta` in sync with the write we just made (recording how many bytes of the
+        // file are now accounted for in `sled`) so the next startup's `read_from_fs` doesn't
+        // re-parse, and re-insert under fresh keys, the line we just ingested ourselves.
+        let file_meta_key = history_file_path.to_string_lossy().into_owned();
+        let file_len = history_file_path.metadata()?.len();
+
+        self.file_meta.insert(file_meta_key, file_len.to_be_bytes().to_vec())?;
+
+        Ok(())
+    }
+
+    fn write_last_snapshot(&self, user_name: &str, device_name: &str, location: &Location) -> Result<()> {
+        let last_directory_for_user_and_device = self.base_path.join("last").join(user_name).join(device_name);
+        fs::create_dir_all(&last_directory_for_user_and_device)?;
+
+        let last_file_name = format!("{}-{}.json", user_name, device_name);
+        let last_file_path = last_directory_for_user_and_device.join(last_file_name);
 
-        info!(target: "Storage", "Sorting locations took {:.2?}", started_sorting.elapsed());
+        fs::write(last_file_path, serde_json::to_string(location)?)?;
 
         Ok(())
     }